@@ -1,10 +1,13 @@
 //! keyboard
 
+use std::{ffi::CString, fs::OpenOptions, io::Write, os::raw::c_int, sync::Mutex};
+
 use remacs_macros::lisp_fn;
 
 use crate::{
     buffers::current_buffer,
-    eval::{record_unwind_protect, unbind_to},
+    call,
+    eval::{clear_unwind_protect, record_unwind_protect, unbind_to, xsignal0, xsignal1},
     frames::{selected_frame, window_frame_live_or_selected_with_action},
     lisp::defsubr,
     lisp::LispObject,
@@ -17,12 +20,30 @@ use crate::{
     remacs_sys::{
         make_lispy_position, temporarily_switch_to_single_kboard, window_box_left_offset,
     },
-    remacs_sys::{Fpos_visible_in_window_p, Fthrow},
-    remacs_sys::{Qexit, Qheader_line, Qhelp_echo, Qmode_line, Qnil, Qt, Qvertical_line},
+    remacs_sys::{
+        build_string, message3, message3_nolog, Fcurrent_message, Fexpand_file_name,
+        Fpos_visible_in_window_p, Fthrow,
+    },
+    remacs_sys::{
+        Qbottom_divider, Qerror, Qexit, Qheader_line, Qhelp_echo, Qlite_quit, Qmode_line, Qnil,
+        Qquit, Qright_divider, Qt, Qvertical_line,
+    },
+    remacs_sys::{
+        ctrl_modifier, previous_help_echo_string, shift_modifier, Vpre_help_message,
+        Vshow_help_function,
+    },
     threads::c_specpdl_index,
     windows::{selected_window, LispWindowOrSelected},
 };
 
+lazy_static! {
+    /// The file opened by `open-dribble-file', if any.  Every event the
+    /// command loop reads is appended to it by `write_dribble_char' (control
+    /// and meta characters escaped), which C's `read_char' calls for each
+    /// raw event.
+    static ref DRIBBLE_FILE: Mutex<Option<std::fs::File>> = Mutex::new(None);
+}
+
 /// Return position information for buffer position POS in WINDOW.
 /// POS defaults to point in WINDOW; WINDOW defaults to the selected window.
 ///
@@ -95,6 +116,13 @@ pub fn posn_at_x_y(
         y = w.frame_pixel_y(y);
     });
 
+    // NOTE: which window part the click lands on (AREA-OR-POS becoming a
+    // symbol such as `mode-line', `header-line', `vertical-line',
+    // `right-divider' or `bottom-divider' instead of a buffer position) is
+    // decided entirely inside the C function `make_lispy_position'; this
+    // function only converts X and Y to frame coordinates.
+    // `lucid_event_type_list_p' below rejects those part symbols as
+    // Lucid-style event types.
     unsafe { make_lispy_position(frame.as_mut(), x.into(), y.into(), 0) }
 }
 
@@ -108,6 +136,8 @@ pub fn lucid_event_type_list_p(event: Option<LispCons>) -> bool {
             || first.eq(Qvertical_line)
             || first.eq(Qmode_line)
             || first.eq(Qheader_line)
+            || first.eq(Qright_divider)
+            || first.eq(Qbottom_divider)
         {
             return false;
         }
@@ -122,16 +152,20 @@ pub fn lucid_event_type_list_p(event: Option<LispCons>) -> bool {
     })
 }
 
-pub fn quit_recursive_edit(val: bool) -> ! {
+fn throw_to_exit(val: LispObject) -> ! {
     unsafe {
         if command_loop_level > 0 || minibuf_level > 0 {
-            Fthrow(Qexit, val.into());
+            Fthrow(Qexit, val);
         }
 
         user_error!("No recursive edit is in progress");
     }
 }
 
+pub fn quit_recursive_edit(val: bool) -> ! {
+    throw_to_exit(val.into());
+}
+
 /// Exit from the innermost recursive edit or minibuffer.
 #[lisp_fn(intspec = "")]
 pub fn exit_recursive_edit() -> ! {
@@ -144,13 +178,44 @@ pub fn abort_recursive_edit() -> ! {
     quit_recursive_edit(true);
 }
 
+/// Function bound to the symbol `lite-quit'.
+/// `recursive-edit' funcalls a value thrown to the `exit' tag when that
+/// value is callable, so binding the error symbol itself to this function
+/// lets `lite-quit-recursive-edit' throw the symbol directly.  This just
+/// echoes a message; it does not signal `lite-quit', because a signal
+/// would unwind to the ordinary command-loop error handler, which clears
+/// the flag that keeps a running keyboard macro going -- exactly what
+/// this function exists to avoid.
+#[lisp_fn]
+pub fn lite_quit() {
+    let message = CString::new("Quit").unwrap();
+    unsafe { message3_nolog(build_string(message.as_ptr())) };
+}
+
+/// Abort the innermost recursive edit or minibuffer without aborting a
+/// keyboard macro that is executing in an outer command loop.
+/// Unlike `abort-recursive-edit', this throws a callable to the `exit' tag;
+/// `recursive-edit' funcalls it and returns normally instead of propagating
+/// the quit further up, so `execute-kbd-macro' keeps running.
+#[lisp_fn(intspec = "")]
+pub fn lite_quit_recursive_edit() -> ! {
+    throw_to_exit(Qlite_quit);
+}
+
 /// Invoke the editor command loop recursively.
 /// To get out of the recursive edit, a command can throw to `exit' -- for
 /// instance (throw \\='exit nil).
 /// If you throw a value other than t, `recursive-edit' returns normally
 /// to the function that called it.  Throwing a t value causes
 /// `recursive-edit' to quit, so that control returns to the command loop
-/// one level up.
+/// one level up.  Throwing a string signals it as an error.  Throwing a
+/// callable value (as `lite-quit-recursive-edit' does) funcalls it and
+/// also returns normally, without aborting a keyboard macro running in
+/// an outer command loop.
+///
+/// `recursive_edit_1' now hands back the raw value thrown to `exit'
+/// instead of special-casing it itself, so all of the above is handled
+/// here rather than on the C side.
 ///
 /// This function is called by the editor initialization to begin editing.
 #[lisp_fn(intspec = "")]
@@ -185,13 +250,211 @@ pub fn recursive_edit() {
             temporarily_switch_to_single_kboard(selected_frame().as_mut());
         }
 
-        recursive_edit_1();
+        let thrown = recursive_edit_1();
         unbind_to(count, Qnil);
+
+        // `recursive_edit_1' hands back the raw value thrown to `exit'.
+        // Reproduce the cases it used to handle internally: `t' quits,
+        // a string is signaled as an error, and anything else is just
+        // returned to the caller -- except a callable value (as thrown
+        // by `lite-quit-recursive-edit'), which is funcalled here and
+        // swallowed so the quit doesn't propagate further and abort a
+        // keyboard macro running in an outer command loop.
+        if thrown.eq(Qt) {
+            xsignal0(Qquit);
+        } else if thrown.is_string() {
+            xsignal1(Qerror, thrown);
+        } else if thrown.is_function() {
+            call!(thrown);
+        }
     }
 }
 
+fn close_dribble_file() {
+    if let Some(mut file) = DRIBBLE_FILE.lock().unwrap().take() {
+        let _ = file.flush();
+    }
+}
+
+extern "C" fn close_dribble_file_unwind(_: LispObject) -> LispObject {
+    close_dribble_file();
+    Qnil
+}
+
+/// Append a single character read by the command loop to the dribble
+/// file, if one is open.  Printable characters are written verbatim;
+/// control and meta characters are escaped so the log stays readable.
+/// Called from the C `read_char' path for every raw input event.
+#[no_mangle]
+pub extern "C" fn write_dribble_char(c: c_int) {
+    let mut dribble = DRIBBLE_FILE.lock().unwrap();
+    let file = match dribble.as_mut() {
+        Some(file) => file,
+        None => return,
+    };
+
+    let c = c as u32;
+    if c & !0o177 != 0 {
+        let _ = write!(file, "\\{:o}", c);
+    } else if c == 0o33 {
+        let _ = write!(file, "\\e");
+    } else if c < 0o40 || c == 0o177 {
+        let _ = write!(file, "^{}", (c ^ 0o100) as u8 as char);
+    } else {
+        let _ = write!(file, "{}", c as u8 as char);
+    }
+    let _ = file.flush();
+}
+
+/// Start writing all keyboard characters to a dribble file called FILE.
+/// If FILE is nil, close any open dribble file.
+/// The file will be closed when Emacs exits.
+#[lisp_fn]
+pub fn open_dribble_file(file: LispObject) {
+    close_dribble_file();
+
+    if file.is_not_nil() {
+        let expanded = unsafe { Fexpand_file_name(file, Qnil) };
+        let path = expanded.force_string().to_utf8();
+
+        let count = c_specpdl_index();
+        unsafe { record_unwind_protect(Some(close_dribble_file_unwind), Qnil) };
+
+        let opened = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap_or_else(|_| error!("Cannot open dribble file: {}", path));
+
+        *DRIBBLE_FILE.lock().unwrap() = Some(opened);
+
+        // The handle is safely stored now; disarm the unwind-protect so
+        // unbind_to doesn't immediately close the file we just opened.
+        unsafe {
+            clear_unwind_protect(count);
+            unbind_to(count, Qnil);
+        }
+    }
+}
+
+/// Display HELP as help-echo text, following `show-help-function' and
+/// `pre-help-message'.  HELP may be a string, a function to call for
+/// its text, or nil to clear a previously displayed help-echo message.
+/// Called from the C redisplay and mouse-tracking code whenever the
+/// `help-echo' property under the mouse changes.
+///
+/// The previous echo-area contents are remembered in the C global
+/// `previous_help_echo_string' (staticpro'd on the C side) rather than
+/// in a Rust-owned static, since a bare Rust static holding a Lisp
+/// object is not a GC root and the saved string could be collected out
+/// from under us before we read it back.
+#[no_mangle]
+pub extern "C" fn show_help_echo(mut help: LispObject) {
+    unsafe {
+        if help.is_not_nil() && !help.is_string() {
+            help = call!(help);
+        }
+
+        if Vshow_help_function.is_not_nil() {
+            call!(Vshow_help_function, help);
+            return;
+        }
+
+        if previous_help_echo_string.is_nil() {
+            previous_help_echo_string = Fcurrent_message();
+        }
+
+        // Compute what to show before emitting it: `message3'/`message3_nolog'
+        // can drive redisplay and re-enter this function, and nothing here
+        // is guarded by a lock, so there is nothing left to hold across them.
+        if help.is_string() {
+            // `pre-help-message' is meant to be seen, not instantly
+            // clobbered by the real help text in the same redisplay
+            // cycle, so show it as a prefix of the final message rather
+            // than in a separate message3 call.
+            let text = if Vpre_help_message.is_string() {
+                let pre = Vpre_help_message.force_string().to_utf8();
+                let rest = help.force_string().to_utf8();
+                match CString::new(format!("{}{}", pre, rest)) {
+                    Ok(combined) => build_string(combined.as_ptr()),
+                    Err(_) => help,
+                }
+            } else {
+                help
+            };
+            message3_nolog(text);
+        } else {
+            let previous = previous_help_echo_string;
+            previous_help_echo_string = Qnil;
+            if previous.is_not_nil() {
+                message3(previous);
+            } else {
+                message3(Qnil);
+            }
+        }
+    }
+}
+
+/// Return the canonical event code for character C with the control
+/// modifier applied.  Letters and the characters `@[\]^_' are folded
+/// into the 0-31 control range (preserving a shift modifier for a
+/// shifted upper-case letter); other printable ASCII characters (such
+/// as space, digits and `/'), and any C that is not plain ASCII, get
+/// the ctrl modifier bit OR'd in instead, since the basic character
+/// code can't represent it.
+/// Called from the C lispy-event construction path so that Ctrl
+/// combinations produce the same key codes as stock Emacs, including
+/// for keys like `C-/' and `C-SPC'.
+#[no_mangle]
+pub extern "C" fn make_ctrl_char(c: c_int) -> c_int {
+    // Save the upper (modifier) bits here.
+    let upper = c & !0o177;
+
+    if upper != 0 {
+        // C isn't a plain ASCII character once its modifier bits are
+        // accounted for; OR in the ctrl modifier since we can't fold
+        // it into the ASCII control range.
+        return c | ctrl_modifier as c_int;
+    }
+
+    let mut c = c & 0o177;
+
+    if c >= 0o100 && c < 0o140 {
+        // Columns containing the upper-case letters denote control
+        // characters; keep the shift modifier if the letter was
+        // actually shifted.
+        let oc = c;
+        c &= !0o140;
+        if oc >= b'A' as c_int && oc <= b'Z' as c_int {
+            c |= shift_modifier as c_int;
+        }
+    } else if c >= b'a' as c_int && c <= b'z' as c_int {
+        c &= !0o140;
+    } else if c >= b' ' as c_int {
+        c |= ctrl_modifier as c_int;
+    }
+
+    // Folding the base character into the ASCII control range already
+    // accounts for ctrl; strip it from the saved upper bits so it isn't
+    // redundantly left set.
+    c | (upper & !(ctrl_modifier as c_int))
+}
+
 #[no_mangle]
 pub extern "C" fn rust_syms_of_keyboard() {
+    /// Function to call to display a help-echo message, instead of
+    /// showing it in the echo area.
+    /// It is called with one argument, the help string to display, and
+    /// may pop up a tooltip or other widget instead.  If this function
+    /// does its own display, it should store nil in `this-command-keys'.
+    defvar_lisp!(Vshow_help_function, "show-help-function", Qnil);
+
+    /// If a string, it is displayed in the echo area in front of each
+    /// help-echo message, as a prefix of that message.
+    /// This is only used when `show-help-function' is nil.
+    defvar_lisp!(Vpre_help_message, "pre-help-message", Qnil);
+
     /// The last command executed.
     /// Normally a symbol with a function definition, but can be whatever was found
     /// in the keymap, or whatever the variable `this-command' was set to by that